@@ -1,28 +1,427 @@
 use std::net::TcpListener;
-use std::str::Split;
 use std::{
-    borrow::Cow,
     collections::HashMap,
     env,
     fs::{self, File},
-    io::{Read, Write},
+    io::{BufRead, BufReader, Read, Write},
     net::TcpStream,
     path::{Path, PathBuf},
     thread,
+    time::Duration,
 };
 
-use flate2::write::GzEncoder;
+use brotli::CompressorWriter;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
 use flate2::Compression;
 use threadpool::ThreadPool;
 
 const USER_AGENT: &str = "User-Agent";
 const PATH: &str = "Path";
-const SUPPORTED_ENCODING: &str = "gzip";
-const N_WORKERS: usize = 5;
+const DEFAULT_WORKERS: usize = 5;
+/// How long a keep-alive connection may sit idle before the worker gives up
+/// on it and moves on to the next connection.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(20);
+/// Largest `Content-Length` we'll believe before reading a body. Caps the
+/// up-front allocation in `read_request` so a client can't claim a
+/// multi-gigabyte body and exhaust the worker's memory before we've even
+/// checked it's well-formed.
+const MAX_BODY_LEN: usize = 10 * 1024 * 1024;
+
+/// One entry in the routing table: a path pattern and the methods it
+/// accepts. `exact` patterns must match the whole path; non-exact ones
+/// match the pattern itself or anything nested under it (`/files` also
+/// matches `/files/foo.txt`).
+struct Route {
+    pattern: &'static str,
+    exact: bool,
+    methods: &'static [&'static str],
+}
+
+const ROUTES: &[Route] = &[
+    Route {
+        pattern: "/",
+        exact: true,
+        methods: &["GET"],
+    },
+    Route {
+        pattern: "/user-agent",
+        exact: true,
+        methods: &["GET"],
+    },
+    Route {
+        pattern: "/echo",
+        exact: false,
+        methods: &["GET"],
+    },
+    Route {
+        pattern: "/files",
+        exact: false,
+        methods: &["GET", "POST"],
+    },
+];
+
+fn match_route(path: &str) -> Option<&'static Route> {
+    ROUTES.iter().find(|route| {
+        if route.exact {
+            path == route.pattern
+        } else {
+            match path.strip_prefix(route.pattern) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                None => false,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod match_route_tests {
+    use super::*;
+
+    #[test]
+    fn matches_an_exact_route() {
+        assert_eq!(match_route("/").map(|r| r.pattern), Some("/"));
+    }
+
+    #[test]
+    fn exact_route_does_not_match_a_nested_path() {
+        assert!(match_route("/user-agent/extra").is_none());
+    }
+
+    #[test]
+    fn prefix_route_matches_a_nested_path() {
+        assert_eq!(
+            match_route("/files/foo.txt").map(|r| r.pattern),
+            Some("/files")
+        );
+    }
+
+    #[test]
+    fn prefix_route_does_not_match_a_different_path_sharing_the_prefix() {
+        // "/filesystem" shares the "/files" prefix as a string but isn't
+        // the "/files" route or anything nested under it.
+        assert!(match_route("/filesystem").is_none());
+    }
+
+    #[test]
+    fn unknown_path_matches_nothing() {
+        assert!(match_route("/nope").is_none());
+    }
+}
+
+/// A response with no body, framed with an explicit `Content-Length: 0` so
+/// it stays unambiguous when the connection is kept alive — per HTTP/1.1,
+/// a response with neither `Content-Length` nor `Transfer-Encoding` reads
+/// as "body extends until connection close", which a keep-alive response
+/// must never imply.
+fn empty_response(status_line: &str) -> Vec<u8> {
+    format!("{}Content-Length: 0\r\n\r\n", status_line).into_bytes()
+}
+
+fn method_not_allowed(allowed: &[&str]) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 405 Method Not Allowed\r\nAllow: {}\r\nContent-Length: 0\r\n\r\n",
+        allowed.join(", ")
+    )
+    .into_bytes()
+}
+
+/// Truncates a response down to its header block, for `HEAD` requests:
+/// the `Content-Length` the GET handler computed is kept, only the body
+/// bytes following it are dropped.
+fn strip_to_headers(response: Vec<u8>) -> Vec<u8> {
+    match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => response[..i + 4].to_vec(),
+        None => response,
+    }
+}
+
+/// Content codings this server knows how to produce, in server preference
+/// order (used for `q`-value ties and for `*` fallback).
+const SUPPORTED_CODINGS: [(&str, Encoding); 3] = [
+    ("gzip", Encoding::Gzip),
+    ("deflate", Encoding::Deflate),
+    ("br", Encoding::Brotli),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+    Brotli,
+    Identity,
+}
+
+impl Encoding {
+    fn header_value(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Deflate => Some("deflate"),
+            Encoding::Brotli => Some("br"),
+            Encoding::Identity => None,
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Encoding::Brotli => {
+                let mut output = Vec::new();
+                {
+                    let mut writer = CompressorWriter::new(&mut output, 4096, 5, 22);
+                    writer.write_all(data).unwrap();
+                }
+                output
+            }
+            Encoding::Identity => data.to_vec(),
+        }
+    }
+}
+
+/// Picks the best content-coding for an `Accept-Encoding` header, per
+/// RFC 7231 §5.3.4: entries are `coding[;q=value]` separated by commas,
+/// a missing `q` defaults to `1.0`, and `q=0` rules a coding out entirely.
+/// `*` matches any coding this server supports that wasn't named explicitly,
+/// and `identity;q=0` rules out sending the response uncompressed.
+fn negotiate_encoding(header: Option<&str>) -> Encoding {
+    let header = match header {
+        Some(header) => header,
+        None => return Encoding::Identity,
+    };
+
+    let mut candidates: Vec<(String, f32)> = Vec::new();
+    // Every coding named explicitly, regardless of its q-value — the
+    // wildcard may only fill in codings that weren't named at all, not
+    // ones the client named and then set to q=0.
+    let mut named_codings: Vec<String> = Vec::new();
+    let mut wildcard_q: Option<f32> = None;
+    let mut identity_forbidden = false;
+
+    for entry in header.split(',') {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+
+        let mut parts = entry.splitn(2, ";q=");
+        let coding = parts.next().unwrap_or("").trim().to_lowercase();
+        let q: f32 = parts
+            .next()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(1.0);
+
+        if coding == "*" {
+            if q > 0.0 {
+                wildcard_q = Some(q);
+            }
+            continue;
+        }
+
+        named_codings.push(coding.clone());
+
+        if coding == "identity" && q == 0.0 {
+            identity_forbidden = true;
+        }
+
+        if q > 0.0 {
+            candidates.push((coding, q));
+        }
+    }
+
+    let mut best: Option<(Encoding, f32, usize)> = None;
+    for (coding, q) in &candidates {
+        if let Some(rank) = SUPPORTED_CODINGS
+            .iter()
+            .position(|(name, _)| *name == coding)
+        {
+            let is_better = match best {
+                None => true,
+                Some((_, best_q, best_rank)) => *q > best_q || (*q == best_q && rank < best_rank),
+            };
+            if is_better {
+                best = Some((SUPPORTED_CODINGS[rank].1, *q, rank));
+            }
+        }
+    }
+
+    if let Some(q) = wildcard_q {
+        for (rank, (name, encoding)) in SUPPORTED_CODINGS.iter().enumerate() {
+            if named_codings.iter().any(|coding| coding == name) {
+                continue;
+            }
+            let is_better = match best {
+                None => true,
+                Some((_, best_q, best_rank)) => q > best_q || (q == best_q && rank < best_rank),
+            };
+            if is_better {
+                best = Some((*encoding, q, rank));
+            }
+        }
+    }
+
+    match best {
+        Some((encoding, _, _)) => encoding,
+        // Client refuses identity but none of its listed codings are ones we
+        // support: best effort is our most-preferred coding rather than
+        // sending the uncompressed body it explicitly forbade.
+        None if identity_forbidden => SUPPORTED_CODINGS[0].1,
+        None => Encoding::Identity,
+    }
+}
+
+#[cfg(test)]
+mod negotiate_encoding_tests {
+    use super::*;
+
+    #[test]
+    fn defaults_to_identity_without_header() {
+        assert_eq!(negotiate_encoding(None), Encoding::Identity);
+    }
+
+    #[test]
+    fn picks_highest_q_value() {
+        assert_eq!(
+            negotiate_encoding(Some("deflate;q=0.5, gzip;q=0.8")),
+            Encoding::Gzip
+        );
+    }
+
+    #[test]
+    fn ties_break_by_server_preference_order() {
+        assert_eq!(
+            negotiate_encoding(Some("br;q=1.0, gzip;q=1.0")),
+            Encoding::Gzip
+        );
+    }
+
+    #[test]
+    fn wildcard_fills_in_an_unlisted_supported_coding() {
+        assert_eq!(negotiate_encoding(Some("*;q=1")), Encoding::Gzip);
+    }
+
+    #[test]
+    fn wildcard_does_not_override_a_coding_the_client_explicitly_forbade() {
+        // Regression test: the client named gzip and forbade it with q=0,
+        // so the wildcard must not be allowed to select gzip anyway.
+        assert_eq!(
+            negotiate_encoding(Some("gzip;q=0, *;q=1")),
+            Encoding::Deflate
+        );
+    }
+
+    #[test]
+    fn identity_q_zero_forces_a_compressed_best_effort() {
+        assert_eq!(
+            negotiate_encoding(Some("identity;q=0")),
+            SUPPORTED_CODINGS[0].1
+        );
+    }
+}
+
+/// Whether `coding` is acceptable under `header`, used to decide if a
+/// pre-compressed `.gz` sidecar can be streamed as-is for a request.
+fn accepts_coding(header: Option<&str>, coding: &str) -> bool {
+    let header = match header {
+        Some(header) => header,
+        None => return false,
+    };
+
+    let mut explicit_q: Option<f32> = None;
+    let mut wildcard_q: Option<f32> = None;
+
+    for entry in header.split(',') {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let mut parts = entry.splitn(2, ";q=");
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let q: f32 = parts
+            .next()
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(1.0);
+
+        if name == coding {
+            explicit_q = Some(q);
+        } else if name == "*" {
+            wildcard_q = Some(q);
+        }
+    }
+
+    match explicit_q {
+        Some(q) => q > 0.0,
+        None => wildcard_q.is_some_and(|q| q > 0.0),
+    }
+}
+
+#[cfg(test)]
+mod accepts_coding_tests {
+    use super::*;
+
+    #[test]
+    fn no_header_means_not_accepted() {
+        assert!(!accepts_coding(None, "gzip"));
+    }
+
+    #[test]
+    fn explicit_listing_with_positive_q_is_accepted() {
+        assert!(accepts_coding(Some("deflate, gzip;q=0.5"), "gzip"));
+    }
+
+    #[test]
+    fn explicit_q_zero_is_rejected_even_with_wildcard() {
+        assert!(!accepts_coding(Some("gzip;q=0, *;q=1"), "gzip"));
+    }
+
+    #[test]
+    fn wildcard_accepts_an_unlisted_coding() {
+        assert!(accepts_coding(Some("*;q=1"), "gzip"));
+    }
+
+    #[test]
+    fn wildcard_q_zero_accepts_nothing() {
+        assert!(!accepts_coding(Some("*;q=0"), "gzip"));
+    }
+}
+
+/// Reads `--workers <n>` off the CLI args, falling back to
+/// `DEFAULT_WORKERS` so keep-alive connections don't starve the pool when
+/// the operator hasn't sized it for their traffic. `0` is rejected the same
+/// as a missing/invalid value — `ThreadPool::new` panics on it outright, and
+/// a typo shouldn't crash the server instead of just being ignored.
+fn parse_workers_arg(args: &[String]) -> usize {
+    args.iter()
+        .position(|arg| arg == "--workers")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|value| value.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_WORKERS)
+}
+
+/// Reads `--directory <path>` off the CLI args, the root `/files` is served
+/// from and written under. Falls back to `.` when the flag is absent or has
+/// no value after it.
+fn parse_directory_arg(args: &[String]) -> String {
+    args.iter()
+        .position(|arg| arg == "--directory")
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+        .unwrap_or_else(|| ".".to_string())
+}
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
     let listener = TcpListener::bind("127.0.0.1:4221").unwrap();
-    let pool = ThreadPool::new(N_WORKERS);
+    let pool = ThreadPool::new(parse_workers_arg(&args));
 
     for stream in listener.incoming() {
         match stream {
@@ -42,140 +441,502 @@ fn main() {
     }
 }
 
-fn parse_request(request_str: Cow<'_, str>) -> HashMap<String, String> {
+/// Reads the request line and headers off `reader` one line at a time up to
+/// the blank `\r\n\r\n`, then — guided by `Content-Length` — reads exactly
+/// that many bytes for the body. The body is kept as raw bytes rather than
+/// folded into the header map, so binary POST payloads survive intact.
+///
+/// Takes the connection's `BufReader` by reference rather than constructing
+/// one per call: a fresh `BufReader` would silently discard any bytes it
+/// had already buffered past the current request (e.g. a pipelined next
+/// request), starving the following `read_request` call until it times out.
+///
+/// Returns `None` if the connection was closed, the idle read timeout
+/// elapsed, the request line didn't parse, or `Content-Length` exceeded
+/// `MAX_BODY_LEN` — in each case the caller
+/// simply drops the connection instead of treating it as a request.
+fn read_request(
+    reader: &mut BufReader<&mut TcpStream>,
+) -> Option<(HashMap<String, String>, Vec<u8>)> {
     let mut details = HashMap::new();
 
-    let mut split_request = request_str.split("\r\n");
-
-    // Parse first line (Type Path Version)
-    if let Some(str) = split_request.next() {
-        let str_split: Vec<&str> = str.split(" ").collect();
-        // println!("str_split: {:#?}", str_split);
-        details.insert("Type".to_string(), str_split[0].to_string());
-        details.insert("Path".to_string(), str_split[1].to_string());
-        details.insert("Version".to_string(), str_split[2].to_string());
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).ok()? == 0 {
+        return None;
     }
+    let str_split: Vec<&str> = request_line.trim_end_matches("\r\n").split(' ').collect();
+    if str_split.len() < 3 {
+        return None;
+    }
+    details.insert("Type".to_string(), str_split[0].to_string());
+    details.insert("Path".to_string(), str_split[1].to_string());
+    details.insert("Version".to_string(), str_split[2].to_string());
 
-    for data in split_request {
-        let data_split: Vec<&str> = data.split(": ").collect();
-        // println!("header_split: {:#?}", header_split);
-
-        if data_split.len() == 2 {
-            details.insert(data_split[0].to_string(), data_split[1].to_string());
-        } else if data_split.len() == 1 {
-            details.insert("Body".to_string(), data_split[0].to_string());
+    loop {
+        let mut header_line = String::new();
+        if reader.read_line(&mut header_line).ok()? == 0 {
+            return None;
+        }
+        let header_line = header_line.trim_end_matches("\r\n");
+        if header_line.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = header_line.split_once(": ") {
+            details.insert(key.to_string(), value.to_string());
         }
     }
 
+    let content_length: usize = details
+        .get("Content-Length")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    if content_length > MAX_BODY_LEN {
+        return None;
+    }
+    let mut body = vec![0u8; content_length];
+    if content_length > 0 {
+        reader.read_exact(&mut body).ok()?;
+    }
+
     println!("details hashmap: {:#?}", details);
-    details
+    Some((details, body))
+}
+
+/// Builds a full HTTP response, compressing `body` with `encoding` and
+/// emitting the matching `Content-Encoding`/`Content-Length` headers.
+fn build_response(
+    status_line: &str,
+    content_type: &str,
+    body: &[u8],
+    encoding: Encoding,
+) -> Vec<u8> {
+    let payload = encoding.encode(body);
+    build_raw_response(status_line, content_type, &payload, encoding.header_value())
+}
+
+/// Builds a full HTTP response from a `payload` that is already in its
+/// final, on-the-wire form (e.g. a pre-compressed file read straight off
+/// disk), so no further encoding is applied.
+fn build_raw_response(
+    status_line: &str,
+    content_type: &str,
+    payload: &[u8],
+    content_encoding: Option<&str>,
+) -> Vec<u8> {
+    let mut response = Vec::new();
+    response.extend_from_slice(status_line.as_bytes());
+    response.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+
+    if let Some(coding) = content_encoding {
+        response.extend_from_slice(format!("Content-Encoding: {}\r\n", coding).as_bytes());
+    }
+    response.extend_from_slice(format!("Content-Length: {}\r\n\r\n", payload.len()).as_bytes());
+    response.extend_from_slice(payload);
+
+    response
 }
 
+/// Handles a connection for as many successive requests as the client keeps
+/// sending: each loop iteration reads one request and writes one response,
+/// until the client sends `Connection: close`, the peer closes the socket,
+/// or the connection sits idle past `IDLE_TIMEOUT`.
 fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    let bytes = stream.read(&mut buffer).unwrap();
-    // IMPORTANT! Parse exactly as many bytes as have been read!
-    let request_str = String::from_utf8_lossy(&buffer[..bytes]);
+    if stream.set_read_timeout(Some(IDLE_TIMEOUT)).is_err() {
+        return;
+    }
+
+    let mut reader = BufReader::new(&mut stream);
+
+    loop {
+        let (request_details, body) = match read_request(&mut reader) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+
+        println!("request details: {:#?}", request_details);
+
+        let encoding =
+            negotiate_encoding(request_details.get("Accept-Encoding").map(String::as_str));
+        let method = request_details.get("Type").unwrap().as_str();
+        let path = request_details.get(PATH).unwrap().as_str();
+        let is_head = method == "HEAD";
+        // HEAD runs the matched GET handler and the body is stripped afterwards.
+        let effective_method = if is_head { "GET" } else { method };
+        let keep_alive = !request_details
+            .get("Connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+
+        let response = match match_route(path) {
+            None => empty_response("HTTP/1.1 404 Not Found\r\n"),
+            Some(route) if !route.methods.contains(&effective_method) => {
+                method_not_allowed(route.methods)
+            }
+            Some(_) => dispatch(effective_method, path, &request_details, &body, encoding),
+        };
+        let response = if is_head {
+            strip_to_headers(response)
+        } else {
+            response
+        };
+        let response = set_connection_header(response, keep_alive);
+
+        println!("response: {:?}", &response);
 
-    let request_details = parse_request(request_str);
+        if reader.get_mut().write_all(&response).is_err() {
+            return;
+        }
 
-    println!("request details: {:#?}", request_details);
+        if !keep_alive {
+            return;
+        }
+    }
+}
 
-    let mut response: Vec<u8> = Vec::new();
-    let path = request_details.get(PATH).unwrap();
+/// Inserts a `Connection: keep-alive`/`close` header right after the status
+/// line of an already-built response.
+fn set_connection_header(response: Vec<u8>, keep_alive: bool) -> Vec<u8> {
+    let status_line_end = response
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .map(|i| i + 2)
+        .unwrap_or(response.len());
 
+    let mut result = Vec::with_capacity(response.len() + 24);
+    result.extend_from_slice(&response[..status_line_end]);
+    result.extend_from_slice(if keep_alive {
+        b"Connection: keep-alive\r\n"
+    } else {
+        b"Connection: close\r\n"
+    });
+    result.extend_from_slice(&response[status_line_end..]);
+
+    result
+}
+
+fn dispatch(
+    method: &str,
+    path: &str,
+    request_details: &HashMap<String, String>,
+    body: &[u8],
+    encoding: Encoding,
+) -> Vec<u8> {
     if path == "/" {
-        response.extend_from_slice("HTTP/1.1 200 OK\r\n\r\n".as_bytes());
+        empty_response("HTTP/1.1 200 OK\r\n")
     } else if path.starts_with("/echo") {
-        // encodings is a string with the following format: "{encoding1}, {encoding2}, {encoding3}, ..."
-        let encodings = request_details
-            .get("Accept-Encoding")
-            .map_or("invalid", String::as_str);
         let echo = path.trim_start_matches("/echo/");
-        response.extend_from_slice("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n".as_bytes());
-
-        if contains_gzip_encoding(encodings.split(", ")) {
-            response.extend_from_slice("Content-Encoding: gzip\r\n".as_bytes());
-
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-            encoder.write_all(echo.as_bytes()).unwrap();
-            let compressed_data = encoder.finish().unwrap();
-            // println!("compressed data: {:x?}", compressed_data);
-            let content_len = format!("Content-Length: {}\r\n\r\n", compressed_data.len());
-            response.extend_from_slice(content_len.as_bytes());
-            response.extend_from_slice(&compressed_data);
-        } else {
-            response.extend_from_slice(
-                format!("Content-Length: {}\r\n\r\n{}", echo.len(), echo).as_bytes(),
-            );
-        }
+        build_response(
+            "HTTP/1.1 200 OK\r\n",
+            "text/plain",
+            echo.as_bytes(),
+            encoding,
+        )
     } else if path.starts_with("/files") {
         let args: Vec<String> = env::args().collect();
-        let dir = &args[2];
+        let directory = parse_directory_arg(&args);
+        let root_dir = Path::new(&directory);
         let file_name = path.trim_start_matches("/files/");
-        let file_path = Path::new(dir).join(file_name);
-
-        response.extend_from_slice(parse_files_endpoint(&request_details, &file_path).as_bytes());
-    } else if path == "/user-agent" {
-        if let Some(user_agent) = request_details.get(USER_AGENT) {
-            response.extend_from_slice(
-                format!(
-                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
-                    user_agent.len(),
-                    user_agent
-                )
-                .as_bytes(),
-            );
-        }
+
+        parse_files_endpoint(method, request_details, root_dir, file_name, body, encoding)
     } else {
-        response.extend_from_slice("HTTP/1.1 404 Not Found\r\n\r\n".as_bytes());
+        // path == "/user-agent"
+        match request_details.get(USER_AGENT) {
+            Some(user_agent) => build_response(
+                "HTTP/1.1 200 OK\r\n",
+                "text/plain",
+                user_agent.as_bytes(),
+                encoding,
+            ),
+            None => empty_response("HTTP/1.1 404 Not Found\r\n"),
+        }
     }
-
-    println!("response: {:?}", &response);
-
-    stream.write_all(&response).unwrap();
 }
 
-fn parse_files_endpoint(request_details: &HashMap<String, String>, file_path: &PathBuf) -> String {
-    let response: String;
-    let request_type = request_details.get("Type").unwrap().as_str();
+fn parse_files_endpoint(
+    method: &str,
+    request_details: &HashMap<String, String>,
+    root: &Path,
+    file_name: &str,
+    body: &[u8],
+    encoding: Encoding,
+) -> Vec<u8> {
+    let resolved_path = match resolve_safe_path(root, file_name) {
+        Some(path) => path,
+        None => return empty_response("HTTP/1.1 404 Not Found\r\n"),
+    };
 
-    match request_type {
+    match method {
         "GET" => {
-            let file_reader = fs::read_to_string(file_path);
-            match file_reader {
-                Ok(file_contents) => {
-                    response = format!(
-                            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n{}",
-                            file_contents.len(),
-                            file_contents
-                        );
-                }
-                Err(_) => {
-                    response = format!("HTTP/1.1 404 Not Found\r\n\r\n");
-                }
+            let accept_encoding = request_details.get("Accept-Encoding").map(String::as_str);
+            let accepts_gzip = accepts_coding(accept_encoding, "gzip");
+
+            if resolved_path.is_dir() {
+                serve_directory(&resolved_path, accepts_gzip, encoding)
+            } else if resolved_path.is_file() || gz_sidecar_path(&resolved_path).is_file() {
+                serve_file(&resolved_path, accepts_gzip, encoding)
+            } else {
+                empty_response("HTTP/1.1 404 Not Found\r\n")
             }
         }
         "POST" => {
-            let mut file = File::create(file_path).unwrap();
-            let file_content = request_details.get("Body").unwrap();
-            file.write_all(file_content.as_bytes()).unwrap();
-            response = format!("HTTP/1.1 201 Created\r\n\r\n");
-        }
-        _ => {
-            response = format!("HTTP/1.1 404 Not Found\r\n\r\n");
+            let content_encoding = request_details.get("Content-Encoding").map(String::as_str);
+            match decode_body(body, content_encoding) {
+                Ok(decoded) => {
+                    let file = File::create(&resolved_path).and_then(|mut file| {
+                        file.write_all(&decoded)?;
+                        Ok(())
+                    });
+                    match file {
+                        Ok(()) => empty_response("HTTP/1.1 201 Created\r\n"),
+                        Err(_) => empty_response("HTTP/1.1 500 Internal Server Error\r\n"),
+                    }
+                }
+                Err(()) => empty_response("HTTP/1.1 415 Unsupported Media Type\r\n"),
+            }
         }
+        _ => empty_response("HTTP/1.1 404 Not Found\r\n"),
+    }
+}
+
+/// Joins `file_name` onto `root` and makes sure the result cannot escape
+/// `root` via `..` segments (or a symlink that resolves outside it). The
+/// target doesn't need to exist yet — e.g. a POST creating a new file — in
+/// which case its parent directory is canonicalized instead; if that parent
+/// doesn't exist either, the path is rejected.
+fn resolve_safe_path(root: &Path, file_name: &str) -> Option<PathBuf> {
+    let root_canon = fs::canonicalize(root).ok()?;
+    let candidate = root.join(file_name);
+
+    if candidate.exists() {
+        let candidate_canon = fs::canonicalize(&candidate).ok()?;
+        return candidate_canon
+            .starts_with(&root_canon)
+            .then_some(candidate_canon);
+    }
+
+    let parent_canon = fs::canonicalize(candidate.parent()?).ok()?;
+    if !parent_canon.starts_with(&root_canon) {
+        return None;
+    }
+    Some(parent_canon.join(candidate.file_name()?))
+}
+
+#[cfg(test)]
+mod resolve_safe_path_tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    /// A fresh scratch directory under the OS temp dir, unique per test so
+    /// parallel test threads don't trip over each other.
+    fn temp_root() -> PathBuf {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let dir = env::temp_dir().join(format!("resolve_safe_path_tests_{}", id));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn allows_a_plain_file_under_root() {
+        let root = temp_root();
+        fs::write(root.join("hello.txt"), b"hi").unwrap();
+
+        let resolved = resolve_safe_path(&root, "hello.txt").unwrap();
+        assert_eq!(resolved, fs::canonicalize(root.join("hello.txt")).unwrap());
+    }
+
+    #[test]
+    fn allows_a_nonexistent_file_whose_parent_is_under_root() {
+        let root = temp_root();
+
+        let resolved = resolve_safe_path(&root, "new.txt").unwrap();
+        assert_eq!(resolved, fs::canonicalize(&root).unwrap().join("new.txt"));
+    }
+
+    #[test]
+    fn rejects_a_dot_dot_escape_above_root() {
+        let root = temp_root();
+
+        assert!(resolve_safe_path(&root, "../escaped.txt").is_none());
+    }
+
+    #[test]
+    fn rejects_an_absolute_path_outside_root() {
+        let root = temp_root();
+
+        assert!(resolve_safe_path(&root, "/etc/passwd").is_none());
+    }
+}
+
+/// Escapes the characters that matter for both an HTML attribute value and
+/// element text, so a single pass is safe to interpolate into either
+/// position. Entries under `/files` are attacker-controlled (a client can
+/// write any name via `POST /files/<name>`), so directory-listing filenames
+/// must never be interpolated unescaped.
+fn escape_html(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            other => other.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod escape_html_tests {
+    use super::*;
+
+    #[test]
+    fn leaves_plain_text_unchanged() {
+        assert_eq!(escape_html("report.txt"), "report.txt");
     }
 
-    return response;
+    #[test]
+    fn escapes_an_attribute_breakout_attempt() {
+        assert_eq!(
+            escape_html("a\"onmouseover=alert(1)&b.txt"),
+            "a&quot;onmouseover=alert(1)&amp;b.txt"
+        );
+    }
+
+    #[test]
+    fn escapes_all_five_reserved_characters() {
+        assert_eq!(escape_html("&<>\"'"), "&amp;&lt;&gt;&quot;&#39;");
+    }
+}
+
+/// Serves a directory under `/files`: `index.html` inside it if present,
+/// otherwise a generated HTML listing of its entries.
+fn serve_directory(dir_path: &Path, accepts_gzip: bool, encoding: Encoding) -> Vec<u8> {
+    let index_path = dir_path.join("index.html");
+    if index_path.is_file() {
+        return serve_file(&index_path, accepts_gzip, encoding);
+    }
+
+    let mut entries: Vec<_> = match fs::read_dir(dir_path) {
+        Ok(entries) => entries.filter_map(Result::ok).collect(),
+        Err(_) => return empty_response("HTTP/1.1 404 Not Found\r\n"),
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut listing = String::from("<html><body>\n<ul>\n");
+    for entry in &entries {
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_dir = metadata.is_dir();
+        let href = if is_dir {
+            format!("{}/", name)
+        } else {
+            name.clone()
+        };
+
+        listing.push_str(&format!(
+            "<li><a href=\"{href}\">{name}{slash}</a> ({size} bytes{kind})</li>\n",
+            href = escape_html(&href),
+            name = escape_html(&name),
+            slash = if is_dir { "/" } else { "" },
+            size = metadata.len(),
+            kind = if is_dir { ", directory" } else { "" },
+        ));
+    }
+    listing.push_str("</ul>\n</body></html>");
+
+    build_response(
+        "HTTP/1.1 200 OK\r\n",
+        "text/html",
+        listing.as_bytes(),
+        encoding,
+    )
+}
+
+/// Decodes an uploaded request body according to its `Content-Encoding`,
+/// mirroring the encoder side used for `/echo`. Returns `Err(())` for an
+/// encoding this server doesn't know how to read, and also for a gzip or
+/// deflate body that fails to decode; the caller turns either into a
+/// `415 Unsupported Media Type`.
+fn decode_body(body: &[u8], content_encoding: Option<&str>) -> Result<Vec<u8>, ()> {
+    match content_encoding.map(|coding| coding.trim().to_lowercase()) {
+        None => Ok(body.to_vec()),
+        Some(coding) if coding == "identity" => Ok(body.to_vec()),
+        Some(coding) if coding == "gzip" => {
+            let mut decoder = GzDecoder::new(body);
+            let mut decoded = Vec::new();
+            decoder.read_to_end(&mut decoded).map_err(|_| ())?;
+            Ok(decoded)
+        }
+        Some(coding) if coding == "deflate" => {
+            let mut decoder = ZlibDecoder::new(body);
+            let mut decoded = Vec::new();
+            decoder.read_to_end(&mut decoded).map_err(|_| ())?;
+            Ok(decoded)
+        }
+        Some(_) => Err(()),
+    }
 }
 
-fn contains_gzip_encoding(encodings: Split<&str>) -> bool {
-    for encoding in encodings {
-        if encoding == SUPPORTED_ENCODING {
-            return true;
+/// Serves a GET for `file_path`, preferring a pre-compressed `foo.ext.gz`
+/// sidecar over compressing on the fly when one is present, fresh and the
+/// client accepts gzip. Falls back to reading (and, if requested,
+/// decompressing) whichever of the two actually exists on disk.
+fn serve_file(file_path: &Path, accepts_gzip: bool, encoding: Encoding) -> Vec<u8> {
+    let gz_path = gz_sidecar_path(file_path);
+    let original_meta = fs::metadata(file_path).ok();
+    let gz_meta = fs::metadata(&gz_path).ok();
+
+    let gz_is_fresh = match (&gz_meta, &original_meta) {
+        (Some(gz_meta), Some(original_meta)) => {
+            gz_meta.modified().ok() >= original_meta.modified().ok()
+        }
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+
+    if gz_meta.is_some() && gz_is_fresh {
+        let gz_contents = fs::read(&gz_path).unwrap();
+        if accepts_gzip {
+            return build_raw_response(
+                "HTTP/1.1 200 OK\r\n",
+                "application/octet-stream",
+                &gz_contents,
+                Some("gzip"),
+            );
+        }
+        if original_meta.is_none() {
+            let mut decoder = GzDecoder::new(gz_contents.as_slice());
+            let mut decompressed = Vec::new();
+            decoder.read_to_end(&mut decompressed).unwrap();
+            return build_response(
+                "HTTP/1.1 200 OK\r\n",
+                "application/octet-stream",
+                &decompressed,
+                encoding,
+            );
         }
     }
 
-    false
+    match fs::read(file_path) {
+        Ok(file_contents) => build_response(
+            "HTTP/1.1 200 OK\r\n",
+            "application/octet-stream",
+            &file_contents,
+            encoding,
+        ),
+        Err(_) => empty_response("HTTP/1.1 404 Not Found\r\n"),
+    }
+}
+
+/// Path of the pre-compressed sidecar for a static file, e.g. `foo.txt.gz`
+/// for `foo.txt`.
+fn gz_sidecar_path(file_path: &Path) -> PathBuf {
+    let mut name = file_path.as_os_str().to_owned();
+    name.push(".gz");
+    PathBuf::from(name)
 }